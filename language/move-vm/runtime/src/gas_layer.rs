@@ -1,9 +1,10 @@
 use move_core_types::account_address::AccountAddress;
 use move_core_types::gas_schedule::GasCarrier;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::fmt::{Debug, Write};
 use std::fs::File;
-use std::io::BufWriter;
+use std::io::{self, BufWriter, Stderr};
 use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex, RwLock};
@@ -16,12 +17,251 @@ use tracing_subscriber::registry::{LookupSpan, SpanRef};
 use tracing_subscriber::Layer;
 
 #[derive(Debug)]
-pub struct GasLayer<S, W> {
-    out: Arc<Mutex<W>>,
+pub struct GasLayer<S, W, W2 = Stderr> {
+    trace_out: Arc<Mutex<W>>,
+    summary: Arc<Summary<W2>>,
+    collapsed: Arc<Mutex<HashMap<String, CollapsedSample>>>,
     last_remaining_gas: RwLock<GasCarrier>,
+    format: Format,
+    aggregation: Aggregation,
+    filter: GasFilter,
     _inner: PhantomData<S>,
 }
 
+/// A composable event/span filter for `GasLayer`, modeled on
+/// `tracing_subscriber::filter::Targets`: each directive enables tracing for
+/// one event target or span name, rather than hardcoding `"start"`/`"end"`
+/// and `"root"`/`"call"`/`"call_generic"` in `enabled()`. Defaults to that
+/// same set plus the `"abort"` event target, so instrumenting additional
+/// spans (native-function calls, script prologue/epilogue) or narrowing to a
+/// single module only requires supplying a custom `GasFilter`.
+#[derive(Debug, Clone)]
+pub struct GasFilter {
+    event_targets: HashSet<String>,
+    span_names: HashSet<String>,
+}
+
+impl GasFilter {
+    /// Returns a `GasFilter` that enables nothing; build it up with
+    /// `with_event_target`/`with_span_name`.
+    pub fn empty() -> Self {
+        Self {
+            event_targets: HashSet::new(),
+            span_names: HashSet::new(),
+        }
+    }
+
+    /// Enables the event emitted under `target` (e.g. `"start"`, `"end"`).
+    pub fn with_event_target(mut self, target: impl Into<String>) -> Self {
+        self.event_targets.insert(target.into());
+        self
+    }
+
+    /// Enables the span named `name` (e.g. `"root"`, `"call"`, a
+    /// caller-instrumented span like `"native_call"`).
+    pub fn with_span_name(mut self, name: impl Into<String>) -> Self {
+        self.span_names.insert(name.into());
+        self
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        if metadata.is_event() {
+            self.event_targets.contains(metadata.target())
+        } else if metadata.is_span() {
+            self.span_names.contains(metadata.name())
+        } else {
+            false
+        }
+    }
+}
+
+impl Default for GasFilter {
+    fn default() -> Self {
+        Self::empty()
+            .with_event_target("start")
+            .with_event_target("end")
+            .with_event_target("abort")
+            .with_span_name("root")
+            .with_span_name("call")
+            .with_span_name("call_generic")
+    }
+}
+
+/// Builder for `GasLayer`, letting callers override event/span selection and
+/// wire format before supplying the writer(s) to trace to.
+#[derive(Debug, Default)]
+pub struct Builder {
+    filter: GasFilter,
+    format: Format,
+    aggregation: Aggregation,
+}
+
+impl Builder {
+    /// Overrides which events/spans are traced. Defaults to
+    /// `GasFilter::default()`.
+    pub fn with_targets(mut self, filter: GasFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Overrides the trace sink's wire format. Defaults to
+    /// `Format::FoldedStack`.
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Overrides how samples are turned into trace sink output. Defaults to
+    /// `Aggregation::Collapsed`.
+    pub fn with_aggregation(mut self, aggregation: Aggregation) -> Self {
+        self.aggregation = aggregation;
+        self
+    }
+
+    /// Builds a `GasLayer` that writes samples to `writer` and its
+    /// end-of-run summary to stderr.
+    pub fn build<S, W>(self, writer: W, remaining_gas: GasCarrier) -> GasLayer<S, W, Stderr>
+    where
+        W: std::io::Write + 'static,
+    {
+        self.build_with_writers(writer, io::stderr(), remaining_gas)
+    }
+
+    /// Builds a `GasLayer` that writes samples to `trace` and its
+    /// end-of-run summary to `summary`.
+    pub fn build_with_writers<S, W, W2>(
+        self,
+        trace: W,
+        summary: W2,
+        remaining_gas: GasCarrier,
+    ) -> GasLayer<S, W, W2>
+    where
+        W: std::io::Write + 'static,
+        W2: std::io::Write + 'static,
+    {
+        GasLayer {
+            trace_out: Arc::new(Mutex::new(trace)),
+            summary: Arc::new(Summary::new(summary)),
+            collapsed: Arc::new(Mutex::new(HashMap::new())),
+            last_remaining_gas: RwLock::new(remaining_gas),
+            format: self.format,
+            aggregation: self.aggregation,
+            filter: self.filter,
+            _inner: PhantomData,
+        }
+    }
+}
+
+/// The on-disk shape of the trace sink's output.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// `frame; frame; frame <gas_used>` lines, consumable by `inferno` to
+    /// build a flamegraph.
+    #[default]
+    FoldedStack,
+    /// One self-describing JSON object per sample (each start/end/abort
+    /// event under `Aggregation::Raw`, each unique stack under
+    /// `Aggregation::Collapsed`), preserving fields (like `remaining_gas`)
+    /// that the folded-stack format discards.
+    Json,
+}
+
+/// How samples are turned into trace sink output.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregation {
+    /// Write one line per traced sample, in time order. Simple, but a loop
+    /// executing millions of instructions/calls produces a gigantic,
+    /// redundant trace.
+    Raw,
+    /// Collapse samples that share the exact same stack into a running
+    /// total, writing one `stack total` line per unique stack when the
+    /// `FlushGuard` drops (or `flush()` is called). Mirrors the collapse
+    /// step of flamegraph tooling, but performs it online, bounding output
+    /// to one line per unique stack no matter how many times it's hit.
+    ///
+    /// This is the default, so constructors that want per-event output as it
+    /// happens (e.g. [`GasLayer::new_json`]) must opt into [`Aggregation::Raw`]
+    /// explicitly.
+    #[default]
+    Collapsed,
+}
+
+/// A running total for one unique call stack, accumulated while
+/// `Aggregation::Collapsed` is in effect.
+#[derive(Debug, Clone)]
+struct CollapsedSample {
+    frames: Vec<String>,
+    call_info: SpanCallInfo,
+    gas_used: GasCarrier,
+    remaining_gas: GasCarrier,
+    samples: u64,
+}
+
+/// Diagnostic state accumulated while tracing, rendered as a human-readable
+/// report to its writer when the owning `GasLayer`'s `FlushGuard` drops.
+#[derive(Debug)]
+struct Summary<W2> {
+    out: Mutex<W2>,
+    total_gas_used: RwLock<GasCarrier>,
+    root_call_totals: RwLock<HashMap<String, GasCarrier>>,
+    write_failures: RwLock<u64>,
+}
+
+impl<W2> Summary<W2> {
+    fn new(out: W2) -> Self {
+        Self {
+            out: Mutex::new(out),
+            total_gas_used: RwLock::new(0),
+            root_call_totals: RwLock::new(HashMap::new()),
+            write_failures: RwLock::new(0),
+        }
+    }
+
+    fn record(&self, root_call: Option<&str>, gas_used: GasCarrier) {
+        *self.total_gas_used.write().unwrap() += gas_used;
+        if let Some(root_call) = root_call {
+            *self
+                .root_call_totals
+                .write()
+                .unwrap()
+                .entry(root_call.to_string())
+                .or_insert(0) += gas_used;
+        }
+    }
+
+    fn note_write_failure(&self) {
+        *self.write_failures.write().unwrap() += 1;
+    }
+}
+
+impl<W2> Summary<W2>
+where
+    W2: std::io::Write,
+{
+    fn flush(&self) -> Result<(), Error> {
+        let mut out = self.out.lock().unwrap();
+        let total_gas_used = *self.total_gas_used.read().unwrap();
+        let write_failures = *self.write_failures.read().unwrap();
+
+        let _ = writeln!(out, "gas trace summary:");
+        let _ = writeln!(out, "  total gas consumed: {}", total_gas_used);
+
+        let root_call_totals = self.root_call_totals.read().unwrap();
+        if !root_call_totals.is_empty() {
+            let _ = writeln!(out, "  per-root-call totals:");
+            for (root_call, gas_used) in root_call_totals.iter() {
+                let _ = writeln!(out, "    {}: {}", root_call, gas_used);
+            }
+        }
+
+        if write_failures > 0 {
+            let _ = writeln!(out, "  trace write failures: {}", write_failures);
+        }
+
+        out.flush().map_err(Kind::FlushFile).map_err(Error)
+    }
+}
+
 #[derive(Debug, Clone)]
 struct SpanCallInfo {
     module_address: AccountAddress,
@@ -70,16 +310,19 @@ impl Visit for SpanAttributesVisitor {
     fn record_debug(&mut self, _field: &Field, _value: &dyn Debug) {}
 }
 
-#[derive(Default, Debug, Clone, Copy)]
+#[derive(Default, Debug, Clone)]
 struct GasEvent {
     remaining_gas: GasCarrier,
+    /// Only set on an `"abort"` event, naming the `StatusCode` the VM
+    /// exited with.
+    status_code: Option<String>,
 }
 impl From<GasEventVisitor> for GasEvent {
     fn from(v: GasEventVisitor) -> Self {
         v.inner
     }
 }
-#[derive(Default, Debug, Clone, Copy)]
+#[derive(Default, Debug, Clone)]
 struct GasEventVisitor {
     inner: GasEvent,
 }
@@ -89,49 +332,121 @@ impl Visit for GasEventVisitor {
             self.inner.remaining_gas = value;
         }
     }
-    fn record_debug(&mut self, _field: &Field, _value: &dyn Debug) {}
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "status_code" {
+            self.inner.status_code = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == "status_code" {
+            self.inner.status_code = Some(format!("{:?}", value));
+        }
+    }
 }
 
-impl<S, W> GasLayer<S, W>
+impl<S, W> GasLayer<S, W, Stderr>
 where
-    //S: Subscriber + for<'span> LookupSpan<'span>,
     W: std::io::Write + 'static,
 {
     /// Returns a new `GasLayer` that outputs all folded stack samples to the
-    /// provided writer.
+    /// provided writer, and writes its end-of-run summary to stderr.
+    ///
+    /// Uses the default [`Aggregation::Collapsed`], which writes nothing to
+    /// `writer` until the layer's [`FlushGuard`] drops (or `flush()` is
+    /// called on it) — call [`GasLayer::flush_on_drop`] and keep the guard
+    /// alive for the tracing session's duration, or the trace sink stays
+    /// empty.
     pub fn new(writer: W, remaining_gas: GasCarrier) -> Self {
-        Self {
-            out: Arc::new(Mutex::new(writer)),
-            last_remaining_gas: RwLock::new(remaining_gas),
-            _inner: PhantomData,
-        }
+        Self::with_writers(writer, io::stderr(), remaining_gas)
+    }
+
+    /// Like [`GasLayer::new`], but emits each start/end event as a
+    /// self-describing JSON object instead of a folded-stack line, as it
+    /// happens rather than collapsed at flush time.
+    ///
+    /// Unlike `new`, this opts into [`Aggregation::Raw`], so samples are
+    /// written to `writer` as events occur; no `FlushGuard` is required to
+    /// see output (though one is still needed to flush buffered writers on
+    /// shutdown).
+    pub fn new_json(writer: W, remaining_gas: GasCarrier) -> Self {
+        let mut layer = Self::new(writer, remaining_gas);
+        layer.format = Format::Json;
+        layer.aggregation = Aggregation::Raw;
+        layer
+    }
+}
+
+impl GasLayer<(), ()> {
+    /// Returns a `Builder` for constructing a `GasLayer` with a custom
+    /// [`GasFilter`] and/or [`Format`], defaulting to `GasFilter::default()`,
+    /// [`Format::FoldedStack`] and [`Aggregation::Collapsed`].
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+}
+
+impl<S, W, W2> GasLayer<S, W, W2>
+where
+    //S: Subscriber + for<'span> LookupSpan<'span>,
+    W: std::io::Write + 'static,
+    W2: std::io::Write + 'static,
+{
+    /// Returns a new `GasLayer` that writes the folded gas-stack samples to
+    /// `trace` and a human-readable end-of-run summary (total gas consumed,
+    /// per-root-call totals, trace write failures) to `summary`, so the two
+    /// can be sent to separate destinations (e.g. a file and stderr) without
+    /// interleaving.
+    ///
+    /// Like `new`, this defaults to [`Aggregation::Collapsed`]: call
+    /// [`GasLayer::flush_on_drop`] and hold on to the returned `FlushGuard`,
+    /// or neither writer ever receives a line.
+    pub fn with_writers(trace: W, summary: W2, remaining_gas: GasCarrier) -> Self {
+        Builder::default().build_with_writers(trace, summary, remaining_gas)
+    }
+
+    /// Sets the wire format used for the trace sink. Defaults to
+    /// [`Format::FoldedStack`].
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Sets which events/spans are traced. Defaults to
+    /// `GasFilter::default()`.
+    pub fn with_targets(mut self, filter: GasFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Sets how samples are turned into trace sink output. Defaults to
+    /// `Aggregation::Collapsed`.
+    pub fn with_aggregation(mut self, aggregation: Aggregation) -> Self {
+        self.aggregation = aggregation;
+        self
     }
 
-    /// Returns a `FlushGuard` which will flush the `FlameLayer`'s writer when
+    /// Returns a `FlushGuard` which will flush the `GasLayer`'s writers when
     /// it is dropped, or when `flush` is manually invoked on the guard.
-    pub fn flush_on_drop(&self) -> FlushGuard<W> {
+    pub fn flush_on_drop(&self) -> FlushGuard<W, W2> {
         FlushGuard {
-            out: self.out.clone(),
+            trace_out: self.trace_out.clone(),
+            summary: self.summary.clone(),
+            collapsed: self.collapsed.clone(),
+            format: self.format,
         }
     }
 }
 
-impl<S, W> Layer<S> for GasLayer<S, W>
+impl<S, W, W2> Layer<S> for GasLayer<S, W, W2>
 where
     S: Subscriber + for<'span> LookupSpan<'span>,
     W: std::io::Write + 'static,
+    W2: std::io::Write + 'static,
 {
     fn enabled(&self, metadata: &Metadata<'_>, _ctx: Context<'_, S>) -> bool {
-        // TODO: filter by metadata's target
-        if metadata.is_event() {
-            metadata.target() == "start" || metadata.target() == "end"
-        } else if metadata.is_span() {
-            metadata.name() == "root"
-                || metadata.name() == "call"
-                || metadata.name() == "call_generic"
-        } else {
-            false
-        }
+        self.filter.enabled(metadata)
     }
     fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
         let mut visitor = SpanAttributesVisitor::default();
@@ -143,89 +458,199 @@ where
     fn on_event(&self, _event: &Event<'_>, ctx: Context<'_, S>) {
         let event_name = _event.metadata().target();
         let span_id = _event.parent().unwrap();
-        if event_name == "start" {
-            let gas_event: GasEvent = {
-                let mut visitor = GasEventVisitor::default();
-                _event.record(&mut visitor);
-                visitor.into()
-            };
-            let gas_used = self.gas_used_since_last_event(gas_event.remaining_gas);
 
-            let first = ctx
-                .span(span_id)
-                .expect("expected: span id exists in registry");
+        let gas_event: GasEvent = {
+            let mut visitor = GasEventVisitor::default();
+            _event.record(&mut visitor);
+            visitor.into()
+        };
+        let gas_used = self.gas_used_since_last_event(gas_event.remaining_gas);
+
+        let first = ctx
+            .span(span_id)
+            .expect("expected: span id exists in registry");
 
+        if event_name == "start" {
+            // The root span has no real caller to attribute gas to, so its
+            // own start event is skipped; the first sample is emitted once a
+            // call beneath it starts or ends.
             if first.parent().is_none() {
                 return;
             }
 
-            let mut stack = String::new();
-
-            if let Some(second) = first.parent() {
-                let mut call_stack = second.scope().from_root();
-                if let Some(root) = call_stack.next() {
-                    write(&mut stack, root).expect("expected: write to String never fails");
-                }
-                for parent in call_stack {
-                    stack += "; ";
-                    write(&mut stack, parent).expect("expected: write to String never fails");
-                }
-            }
-            stack += &format!(" {}", gas_used);
-            // write!(&mut stack, " {}", gas_used).expect("expected: write to String never fails");
-            let _ = writeln!(*self.out.lock().unwrap(), "{}", stack);
+            let call_info = first.extensions().get::<SpanCallInfo>().unwrap().clone();
+            let frames = first.parent().map(collect_frames).unwrap_or_default();
+            self.write_sample(Phase::Start, &call_info, &frames, gas_used, gas_event.remaining_gas);
         } else if event_name == "end" {
-            let gas_event: GasEvent = {
-                let mut visitor = GasEventVisitor::default();
-                _event.record(&mut visitor);
-                visitor.into()
-            };
-            let gas_used = self.gas_used_since_last_event(gas_event.remaining_gas);
-
-            let first = ctx
-                .span(span_id)
-                .expect("expected: span id exists in registry");
-
-            let mut stack = String::new();
-
-            {
-                let mut call_stack = first.scope().from_root();
-                if let Some(root) = call_stack.next() {
-                    write(&mut stack, root).expect("expected: write to String never fails");
-                }
-                for parent in call_stack {
-                    stack += "; ";
-                    write(&mut stack, parent).expect("expected: write to String never fails");
-                }
+            let call_info = first.extensions().get::<SpanCallInfo>().unwrap().clone();
+            let frames = collect_frames(first);
+            self.write_sample(Phase::End, &call_info, &frames, gas_used, gas_event.remaining_gas);
+        } else if event_name == "abort" {
+            let call_info = first.extensions().get::<SpanCallInfo>().unwrap().clone();
+            let mut frames = collect_frames(first);
+            // The call stops here, so the leaf frame carries the status the
+            // VM exited with instead of silently truncating the trace.
+            if let Some(leaf) = frames.last_mut() {
+                let status = gas_event.status_code.as_deref().unwrap_or("ABORTED");
+                write!(leaf, " [ABORTED:{}]", status).expect("expected: write to String never fails");
             }
-
-            stack += &format!(" {}", gas_used);
-            let _ = writeln!(*self.out.lock().unwrap(), "{}", stack);
+            self.write_sample(Phase::Abort, &call_info, &frames, gas_used, gas_event.remaining_gas);
         }
     }
 }
 
-impl<S, W> GasLayer<S, W>
+impl<S, W, W2> GasLayer<S, W, W2>
 where
     S: Subscriber + for<'span> LookupSpan<'span>,
     W: std::io::Write + 'static,
+    W2: std::io::Write + 'static,
 {
     fn gas_used_since_last_event(&self, remaining_gas: GasCarrier) -> GasCarrier {
         let gas_used = *self.last_remaining_gas.read().unwrap() - remaining_gas;
         *self.last_remaining_gas.write().unwrap() = remaining_gas;
         gas_used
     }
+
+    /// Writes one sample, in the configured [`Format`], to the trace sink and
+    /// folds its gas usage into the summary.
+    fn write_sample(
+        &self,
+        phase: Phase,
+        call_info: &SpanCallInfo,
+        frames: &[String],
+        gas_used: GasCarrier,
+        remaining_gas: GasCarrier,
+    ) {
+        let root_call = frames.get(1).or_else(|| frames.first());
+        self.summary.record(root_call.map(String::as_str), gas_used);
+
+        match self.aggregation {
+            Aggregation::Raw => {
+                let line = render_line(
+                    self.format,
+                    phase,
+                    call_info,
+                    frames,
+                    gas_used,
+                    remaining_gas,
+                    None,
+                );
+                if writeln!(*self.trace_out.lock().unwrap(), "{}", line).is_err() {
+                    self.summary.note_write_failure();
+                }
+            }
+            Aggregation::Collapsed => {
+                let key = frames.join("; ");
+                let mut collapsed = self.collapsed.lock().unwrap();
+                let entry = collapsed.entry(key).or_insert_with(|| CollapsedSample {
+                    frames: frames.to_vec(),
+                    call_info: call_info.clone(),
+                    gas_used: 0,
+                    remaining_gas,
+                    samples: 0,
+                });
+                entry.gas_used += gas_used;
+                entry.remaining_gas = remaining_gas;
+                entry.samples += 1;
+            }
+        }
+    }
+}
+
+/// Renders one sample in the given `format`. `samples` is `Some` only when
+/// rendering a collapsed (aggregated) entry, and adds a sample count to the
+/// JSON representation.
+fn render_line(
+    format: Format,
+    phase: Phase,
+    call_info: &SpanCallInfo,
+    frames: &[String],
+    gas_used: GasCarrier,
+    remaining_gas: GasCarrier,
+    samples: Option<u64>,
+) -> String {
+    match format {
+        Format::FoldedStack => format!("{} {}", frames.join("; "), gas_used),
+        Format::Json => {
+            let samples_field = match samples {
+                Some(n) => format!(",\"samples\":{}", n),
+                None => String::new(),
+            };
+            format!(
+                "{{\"phase\":\"{}\",\"depth\":{},\"stack\":[{}],\"function\":\"{}\",\"module_address\":\"{}\",\"gas_used\":{},\"remaining_gas\":{}{}}}",
+                phase,
+                frames.len(),
+                frames
+                    .iter()
+                    .map(|f| format!("\"{}\"", json_escape(f)))
+                    .collect::<Vec<_>>()
+                    .join(","),
+                json_escape(&call_info.function_name),
+                call_info.module_address,
+                gas_used,
+                remaining_gas,
+                samples_field,
+            )
+        }
+    }
+}
+
+/// Where in a call a gas sample was taken, or that it is a collapsed total.
+#[derive(Debug, Clone, Copy)]
+enum Phase {
+    Start,
+    End,
+    /// The call aborted or the VM ran out of gas; the leaf frame is
+    /// annotated with the `StatusCode` it exited with.
+    Abort,
+    /// A collapsed entry spanning one or more `Start`/`End`/`Abort` samples
+    /// that shared the same stack.
+    Aggregate,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Phase::Start => write!(f, "start"),
+            Phase::End => write!(f, "end"),
+            Phase::Abort => write!(f, "abort"),
+            Phase::Aggregate => write!(f, "aggregate"),
+        }
+    }
 }
-impl<S> GasLayer<S, BufWriter<File>>
+
+/// Escapes characters that are not valid inside a JSON string literal, per
+/// the control-character requirements of RFC 8259 (any of `\"`, `\\`, or a
+/// codepoint below `0x20` makes the output unparseable as JSON otherwise).
+fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                write!(&mut escaped, "\\u{:04x}", c as u32)
+                    .expect("expected: write to String never fails");
+            }
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+impl<S> GasLayer<S, BufWriter<File>, Stderr>
 where
     S: Subscriber + for<'span> LookupSpan<'span>,
 {
-    /// Constructs a `FlameLayer` that outputs to a `BufWriter` to the given path, and a
-    /// `FlushGuard` to ensure the writer is flushed.
+    /// Constructs a `GasLayer` that writes folded stack samples to a
+    /// `BufWriter` over the file at `path`, and its end-of-run summary to
+    /// stderr. Returns a `FlushGuard` to ensure both writers are flushed.
     pub fn with_file(
         path: impl AsRef<Path>,
         remaining_gas: GasCarrier,
-    ) -> Result<(Self, FlushGuard<BufWriter<File>>), Error> {
+    ) -> Result<(Self, FlushGuard<BufWriter<File>, Stderr>), Error> {
         let path = path.as_ref();
         let file = File::create(path)
             .map_err(|source| Kind::CreateFile {
@@ -234,12 +659,30 @@ where
             })
             .map_err(Error)?;
         let writer = BufWriter::new(file);
-        let layer = Self::new(writer, remaining_gas);
+        let layer = Self::with_writers(writer, io::stderr(), remaining_gas);
         let guard = layer.flush_on_drop();
         Ok((layer, guard))
     }
 }
 
+fn render<S>(span: SpanRef<'_, S>) -> String
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let mut dest = String::new();
+    write(&mut dest, span).expect("expected: write to String never fails");
+    dest
+}
+
+/// Renders `span` and every one of its ancestors, root first, as the frame
+/// stack for a sample.
+fn collect_frames<S>(span: SpanRef<'_, S>) -> Vec<String>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    span.scope().from_root().map(render).collect()
+}
+
 fn write<S>(dest: &mut String, _span: SpanRef<'_, S>) -> fmt::Result
 where
     S: Subscriber + for<'span> LookupSpan<'span>,
@@ -277,21 +720,29 @@ where
 /// implementation of layers from running when the program exits.
 #[must_use]
 #[derive(Debug)]
-pub struct FlushGuard<W>
+pub struct FlushGuard<W, W2>
 where
     W: std::io::Write + 'static,
+    W2: std::io::Write + 'static,
 {
-    out: Arc<Mutex<W>>,
+    trace_out: Arc<Mutex<W>>,
+    summary: Arc<Summary<W2>>,
+    collapsed: Arc<Mutex<HashMap<String, CollapsedSample>>>,
+    format: Format,
 }
 
-impl<W> FlushGuard<W>
+impl<W, W2> FlushGuard<W, W2>
 where
     W: std::io::Write + 'static,
+    W2: std::io::Write + 'static,
 {
-    /// Flush the internal writer of the `FlameLayer`, ensuring that all
-    /// intermediately buffered contents reach their destination.
+    /// Flush the trace and summary writers of the `GasLayer`, ensuring that
+    /// all intermediately buffered contents reach their destinations. If
+    /// `Aggregation::Collapsed` is in effect, this is also what writes the
+    /// one-line-per-unique-stack trace out, since nothing was written to the
+    /// trace sink before now.
     pub fn flush(&self) -> Result<(), Error> {
-        let mut guard = match self.out.lock() {
+        let mut guard = match self.trace_out.lock() {
             Ok(guard) => guard,
             Err(e) => {
                 if !std::thread::panicking() {
@@ -302,13 +753,32 @@ where
             }
         };
 
-        guard.flush().map_err(Kind::FlushFile).map_err(Error)
+        for sample in self.collapsed.lock().unwrap().drain().map(|(_, v)| v) {
+            let line = render_line(
+                self.format,
+                Phase::Aggregate,
+                &sample.call_info,
+                &sample.frames,
+                sample.gas_used,
+                sample.remaining_gas,
+                Some(sample.samples),
+            );
+            if writeln!(guard, "{}", line).is_err() {
+                self.summary.note_write_failure();
+            }
+        }
+
+        guard.flush().map_err(Kind::FlushFile).map_err(Error)?;
+        drop(guard);
+
+        self.summary.flush()
     }
 }
 
-impl<W> Drop for FlushGuard<W>
+impl<W, W2> Drop for FlushGuard<W, W2>
 where
     W: std::io::Write + 'static,
+    W2: std::io::Write + 'static,
 {
     fn drop(&mut self) {
         match self.flush() {
@@ -372,3 +842,200 @@ impl fmt::Display for Kind {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::Registry;
+
+    /// An `io::Write` sink that can be cloned and inspected after being
+    /// handed off to a `GasLayer`, since `GasLayer` takes its writers by
+    /// value.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl SharedBuf {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    impl io::Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.lock().unwrap().flush()
+        }
+    }
+
+    /// A `Layer` that records the metadata of every span and event it sees,
+    /// regardless of any filtering, so tests can check `GasFilter::enabled`
+    /// against metadata produced by the real `tracing` macros instead of
+    /// hand-built `Metadata` values.
+    #[derive(Default)]
+    struct MetadataRecorder(Mutex<Vec<&'static Metadata<'static>>>);
+
+    impl<S> Layer<S> for MetadataRecorder
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        fn on_new_span(&self, attrs: &Attributes<'_>, _id: &Id, _ctx: Context<'_, S>) {
+            self.0.lock().unwrap().push(attrs.metadata());
+        }
+
+        fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+            self.0.lock().unwrap().push(event.metadata());
+        }
+    }
+
+    fn call_info(function_name: &str) -> SpanCallInfo {
+        SpanCallInfo {
+            module_address: AccountAddress::ZERO,
+            module_name: "m".to_string(),
+            function_name: function_name.to_string(),
+        }
+    }
+
+    #[test]
+    fn render_line_folded_stack_joins_frames_with_gas_used() {
+        let line = render_line(
+            Format::FoldedStack,
+            Phase::End,
+            &call_info("f"),
+            &["root".to_string(), "call".to_string()],
+            42,
+            958,
+            None,
+        );
+        assert_eq!(line, "root; call 42");
+    }
+
+    #[test]
+    fn render_line_json_includes_phase_stack_and_remaining_gas() {
+        let line = render_line(
+            Format::Json,
+            Phase::Start,
+            &call_info("f"),
+            &["root".to_string(), "call".to_string()],
+            42,
+            958,
+            None,
+        );
+        assert_eq!(
+            line,
+            "{\"phase\":\"start\",\"depth\":2,\"stack\":[\"root\",\"call\"],\"function\":\"f\",\
+             \"module_address\":\"0x0\",\"gas_used\":42,\"remaining_gas\":958}"
+        );
+    }
+
+    #[test]
+    fn render_line_json_appends_samples_for_aggregated_entries() {
+        let line = render_line(
+            Format::Json,
+            Phase::Aggregate,
+            &call_info("f"),
+            &["root".to_string()],
+            42,
+            958,
+            Some(3),
+        );
+        assert!(line.ends_with("\"samples\":3}"));
+    }
+
+    #[test]
+    fn json_escape_escapes_quotes_backslashes_and_control_chars() {
+        assert_eq!(json_escape("plain"), "plain");
+        assert_eq!(json_escape("a\"b\\c"), "a\\\"b\\\\c");
+        assert_eq!(json_escape("line\nbreak\ttab\rcr"), "line\\nbreak\\ttab\\rcr");
+        assert_eq!(json_escape("\u{0}\u{1f}"), "\\u0000\\u001f");
+    }
+
+    #[test]
+    fn collapsed_aggregation_sums_gas_and_counts_samples_per_stack() {
+        let layer = GasLayer::<Registry, SharedBuf, SharedBuf>::with_writers(
+            SharedBuf::default(),
+            SharedBuf::default(),
+            1_000,
+        );
+        let frames = vec!["root".to_string(), "call".to_string()];
+        let info = call_info("f");
+
+        layer.write_sample(Phase::End, &info, &frames, 10, 990);
+        layer.write_sample(Phase::End, &info, &frames, 15, 975);
+
+        let collapsed = layer.collapsed.lock().unwrap();
+        let sample = collapsed.get(&frames.join("; ")).unwrap();
+        assert_eq!(sample.gas_used, 25);
+        assert_eq!(sample.samples, 2);
+        assert_eq!(sample.remaining_gas, 975);
+    }
+
+    #[test]
+    fn abort_event_annotates_leaf_frame_with_status() {
+        let trace = SharedBuf::default();
+        let layer = Builder::default()
+            .with_aggregation(Aggregation::Raw)
+            .build_with_writers(trace.clone(), SharedBuf::default(), 1_000);
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            let root = tracing::trace_span!(
+                "root",
+                module_address = "0x1",
+                module_name = "",
+                function_name = "script"
+            );
+            let _root = root.enter();
+            let call = tracing::trace_span!(
+                "call",
+                module_address = "0x1",
+                module_name = "m",
+                function_name = "f"
+            );
+            let _call = call.enter();
+            tracing::event!(
+                target: "abort",
+                parent: call.id(),
+                tracing::Level::TRACE,
+                remaining_gas = 900u64,
+                status_code = "OUT_OF_GAS",
+            );
+        });
+
+        assert!(trace.contents().contains("[ABORTED:OUT_OF_GAS]"));
+    }
+
+    #[test]
+    fn gas_filter_enabled_matches_default_event_targets_and_span_names() {
+        let recorder = Arc::new(MetadataRecorder::default());
+        let subscriber = Registry::default().with(Arc::clone(&recorder));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let root = tracing::trace_span!("root");
+            let _root = root.enter();
+            let custom = tracing::trace_span!("custom");
+            let _custom = custom.enter();
+            tracing::event!(target: "start", tracing::Level::TRACE, remaining_gas = 1u64);
+            tracing::event!(target: "noise", tracing::Level::TRACE, remaining_gas = 1u64);
+        });
+
+        let seen = recorder.0.lock().unwrap();
+        let filter = GasFilter::default();
+        assert!(filter.enabled(seen[0]), "default filter should enable the \"root\" span");
+        assert!(
+            !filter.enabled(seen[1]),
+            "default filter should not enable an uninstrumented span name"
+        );
+        assert!(
+            filter.enabled(seen[2]),
+            "default filter should enable the \"start\" event target"
+        );
+        assert!(
+            !filter.enabled(seen[3]),
+            "default filter should not enable an unlisted event target"
+        );
+    }
+}
@@ -1,12 +1,29 @@
 use crate::loader::Function;
+use move_binary_format::file_format::Bytecode;
 use move_core_types::gas_schedule::{GasAlgebra, GasCarrier};
+use move_core_types::vm_status::StatusCode;
 use move_vm_types::gas_schedule::GasStatus;
+use std::collections::HashMap;
 use std::fmt::Write;
 #[derive(Default)]
 pub(crate) struct VMTracer {
     tracing: Vec<String>,
     trace_data: Vec<String>,
+    /// Running gas totals keyed by stack, used in place of `trace_data`
+    /// unless `raw_output` is set. Bounds output to one line per unique
+    /// stack, which matters for recursive/looping Move code that would
+    /// otherwise produce one line per call/instruction.
+    collapsed: HashMap<String, GasCarrier>,
     last_remaining_gas: Option<GasCarrier>,
+    /// Whether to additionally fold per-instruction samples into the trace.
+    /// Off by default: most callers only care about function-level
+    /// attribution, and per-instruction tracing adds real overhead to the
+    /// interpreter's hot loop.
+    trace_instructions: bool,
+    /// When true, keep one line per sample in time order (`trace_data`)
+    /// instead of collapsing same-stack samples into a running total. Off
+    /// by default.
+    raw_output: bool,
 }
 
 impl VMTracer {
@@ -18,7 +35,77 @@ impl VMTracer {
 
     #[allow(unused)]
     pub fn get_trace(&self) -> String {
-        self.trace_data.join("\n")
+        if self.raw_output {
+            self.trace_data.join("\n")
+        } else {
+            self.collapsed
+                .iter()
+                .map(|(stack, gas_used)| format!("{} {}", stack, gas_used))
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+    }
+
+    /// Enables or disables folding per-instruction samples into the trace.
+    #[allow(unused)]
+    pub(crate) fn set_trace_instructions(&mut self, trace_instructions: bool) {
+        self.trace_instructions = trace_instructions;
+    }
+
+    /// Switches between collapsing same-stack samples (the default) and
+    /// keeping every sample in time order.
+    #[allow(unused)]
+    pub(crate) fn set_raw_output(&mut self, raw_output: bool) {
+        self.raw_output = raw_output;
+    }
+
+    fn push_sample(&mut self, leaf: Option<&str>, gas_used: GasCarrier) {
+        let mut stack = String::new();
+        let mut call_stack = self.tracing.iter();
+        if let Some(root) = call_stack.next() {
+            write!(&mut stack, "{}", root).expect("expected: write to String never fails");
+        }
+        for call in call_stack {
+            write!(&mut stack, "; {}", call).expect("expected: write to String never fails");
+        }
+        if let Some(leaf) = leaf {
+            write!(&mut stack, "; {}", leaf).expect("expected: write to String never fails");
+        }
+
+        self.record_stack(stack, gas_used);
+    }
+
+    /// Like `push_sample`, but annotates the current leaf frame with the
+    /// status the call aborted or exited with, instead of silently dropping
+    /// it from the trace.
+    fn push_abort_sample(&mut self, status_code: StatusCode, gas_used: GasCarrier) {
+        let mut stack = String::new();
+        let len = self.tracing.len();
+        for (i, frame) in self.tracing.iter().enumerate() {
+            if i > 0 {
+                stack += "; ";
+            }
+            write!(&mut stack, "{}", frame).expect("expected: write to String never fails");
+            if i + 1 == len {
+                write!(&mut stack, " [ABORTED:{:?}]", status_code)
+                    .expect("expected: write to String never fails");
+            }
+        }
+
+        self.record_stack(stack, gas_used);
+    }
+
+    /// Shared sink for a fully-rendered stack string: either appends it to
+    /// `trace_data` in time order, or folds it into the running per-stack
+    /// total, depending on `raw_output`.
+    fn record_stack(&mut self, stack: String, gas_used: GasCarrier) {
+        if self.raw_output {
+            let mut data = stack;
+            write!(&mut data, " {}", gas_used).expect("expected: write to String never fails");
+            self.trace_data.push(data);
+        } else {
+            *self.collapsed.entry(stack).or_insert(0) += gas_used;
+        }
     }
 }
 
@@ -26,16 +113,7 @@ impl Tracer for VMTracer {
     fn trace_function_call_start(&mut self, function: &Function, gas_status: &GasStatus) {
         let gas_used = self.gas_used_since_last_event(gas_status.remaining_gas().get());
         if !self.tracing.is_empty() {
-            let mut data = String::new();
-            let mut call_stack = self.tracing.iter();
-            if let Some(root) = call_stack.next() {
-                write!(&mut data, "{}", root).expect("expected: write to String never fails");
-            }
-            for call in call_stack {
-                write!(&mut data, "; {}", call).expect("expected: write to String never fails");
-            }
-            write!(&mut data, " {}", gas_used).expect("expected: write to String never fails");
-            self.trace_data.push(data);
+            self.push_sample(None, gas_used);
         }
 
         self.tracing.push(function.pretty_string());
@@ -43,23 +121,176 @@ impl Tracer for VMTracer {
 
     fn trace_function_call_end(&mut self, _function: &Function, gas_status: &GasStatus) {
         let gas_used = self.gas_used_since_last_event(gas_status.remaining_gas().get());
-        {
-            let mut data = String::new();
-            let mut call_stack = self.tracing.iter().take(self.tracing.len());
-            if let Some(root) = call_stack.next() {
-                write!(&mut data, "{}", root).expect("expected: write to String never fails");
-            }
-            for call in call_stack {
-                write!(&mut data, "; {}", call).expect("expected: write to String never fails");
-            }
-            write!(&mut data, " {}", gas_used).expect("expected: write to String never fails");
-            self.trace_data.push(data);
+        self.push_sample(None, gas_used);
+        self.tracing.pop().unwrap();
+    }
+
+    fn trace_instruction(
+        &mut self,
+        _function: &Function,
+        pc: u16,
+        opcode: &Bytecode,
+        gas_status: &GasStatus,
+    ) {
+        if !self.trace_instructions {
+            return;
         }
+        let gas_used = self.gas_used_since_last_event(gas_status.remaining_gas().get());
+        self.push_sample(Some(&format!("{}@{}", opcode_mnemonic(opcode), pc)), gas_used);
+    }
 
-        self.tracing.pop().unwrap();
+    fn trace_function_call_abort(
+        &mut self,
+        _function: &Function,
+        status_code: StatusCode,
+        gas_status: &GasStatus,
+    ) {
+        let gas_used = self.gas_used_since_last_event(gas_status.remaining_gas().get());
+        self.push_abort_sample(status_code, gas_used);
+        // An abort unwinds the whole call chain at once, so every frame
+        // pushed by an ancestor's `trace_function_call_start` needs to come
+        // off here too; leaving them behind would corrupt the stack for any
+        // later call traced with this same `VMTracer`.
+        self.tracing.clear();
     }
 }
 pub(crate) trait Tracer {
     fn trace_function_call_start(&mut self, function: &Function, gas_status: &GasStatus);
     fn trace_function_call_end(&mut self, function: &Function, gas_status: &GasStatus);
+
+    /// Per-instruction hook, so implementations that want opcode-level gas
+    /// attribution can fold each instruction into the current call stack as
+    /// an extra leaf frame, charging it the gas used since the previous
+    /// sample. No-op by default: most tracers only need
+    /// function-granularity attribution, and per-instruction tracing adds
+    /// real overhead to the interpreter's hot loop.
+    ///
+    /// The interpreter's instruction loop does not call this method, so no
+    /// per-instruction samples are recorded during execution.
+    fn trace_instruction(
+        &mut self,
+        function: &Function,
+        pc: u16,
+        opcode: &Bytecode,
+        gas_status: &GasStatus,
+    ) {
+        let _ = (function, pc, opcode, gas_status);
+    }
+
+    /// Abort hook, taking the place of `trace_function_call_end` for a call
+    /// that aborted or ran out of gas: records the gas charged up to the
+    /// failure point and marks the leaf frame with `status_code`.
+    ///
+    /// An abort unwinds the whole call chain at once, so implementations
+    /// clear their entire call stack here; a second call for an ancestor
+    /// frame records a bogus, empty-stack sample.
+    ///
+    /// The interpreter's function-call error path does not call this
+    /// method, so an aborted call leaves no abort sample in the trace.
+    fn trace_function_call_abort(
+        &mut self,
+        function: &Function,
+        status_code: StatusCode,
+        gas_status: &GasStatus,
+    ) {
+        let _ = (function, status_code, gas_status);
+    }
+}
+
+/// Renders an opcode as its `SCREAMING_SNAKE_CASE` mnemonic (e.g.
+/// `MoveLoc(0)` -> `MOVE_LOC`) instead of its `Debug` form, so instruction
+/// leaves in the trace read like the rest of the Move bytecode tooling.
+fn opcode_mnemonic(opcode: &Bytecode) -> String {
+    let debug = format!("{:?}", opcode);
+    let name = debug.split('(').next().unwrap_or(&debug);
+
+    let mut mnemonic = String::with_capacity(name.len() + 4);
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            mnemonic.push('_');
+        }
+        mnemonic.push(c.to_ascii_uppercase());
+    }
+    mnemonic
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opcode_mnemonic_converts_camel_case_to_screaming_snake_case() {
+        assert_eq!(opcode_mnemonic(&Bytecode::MoveLoc(0)), "MOVE_LOC");
+        assert_eq!(opcode_mnemonic(&Bytecode::BrTrue(5)), "BR_TRUE");
+        assert_eq!(opcode_mnemonic(&Bytecode::BrFalse(5)), "BR_FALSE");
+    }
+
+    #[test]
+    fn opcode_mnemonic_keeps_a_run_of_uppercase_and_digits_together() {
+        // "LdU64" only has one case transition (lowercase `d` -> uppercase
+        // `U`); the digits that follow aren't uppercase, so they don't split
+        // off their own word.
+        assert_eq!(opcode_mnemonic(&Bytecode::LdU64(42)), "LD_U64");
+    }
+
+    #[test]
+    fn opcode_mnemonic_drops_the_payload_not_just_trailing_digits() {
+        // The split happens on the first `(`, not on trailing digits, so a
+        // payload value that itself ends in digits doesn't leak into the
+        // mnemonic.
+        assert_eq!(opcode_mnemonic(&Bytecode::MoveLoc(64)), "MOVE_LOC");
+    }
+
+    #[test]
+    fn opcode_mnemonic_leaves_a_single_word_opcode_unchanged() {
+        assert_eq!(opcode_mnemonic(&Bytecode::Abort), "ABORT");
+    }
+
+    #[test]
+    fn record_stack_collapses_same_stack_samples_by_default() {
+        let mut tracer = VMTracer::default();
+        tracer.record_stack("root; call".to_string(), 10);
+        tracer.record_stack("root; call".to_string(), 5);
+        tracer.record_stack("root; other".to_string(), 1);
+
+        assert_eq!(tracer.collapsed.get("root; call"), Some(&15));
+        assert_eq!(tracer.collapsed.get("root; other"), Some(&1));
+        assert!(tracer.trace_data.is_empty());
+    }
+
+    #[test]
+    fn record_stack_keeps_one_line_per_sample_in_raw_mode() {
+        let mut tracer = VMTracer::default();
+        tracer.set_raw_output(true);
+        tracer.record_stack("root; call".to_string(), 10);
+        tracer.record_stack("root; call".to_string(), 5);
+
+        assert_eq!(tracer.trace_data, vec!["root; call 10", "root; call 5"]);
+        assert!(tracer.collapsed.is_empty());
+    }
+
+    #[test]
+    fn push_abort_sample_annotates_only_the_leaf_frame_with_the_status() {
+        let mut tracer = VMTracer {
+            tracing: vec!["root".to_string(), "call".to_string()],
+            ..Default::default()
+        };
+        tracer.push_abort_sample(StatusCode::ABORTED, 7);
+
+        assert_eq!(
+            tracer.collapsed.get("root; call [ABORTED:ABORTED]"),
+            Some(&7)
+        );
+    }
+
+    #[test]
+    fn push_abort_sample_on_a_single_frame_stack_annotates_that_frame() {
+        let mut tracer = VMTracer {
+            tracing: vec!["root".to_string()],
+            ..Default::default()
+        };
+        tracer.push_abort_sample(StatusCode::OUT_OF_GAS, 3);
+
+        assert_eq!(tracer.collapsed.get("root [ABORTED:OUT_OF_GAS]"), Some(&3));
+    }
 }